@@ -1,6 +1,10 @@
 use chrono::{NaiveDateTime, Utc};
 
+use rocket::http::Status;
+
 use super::User;
+use push;
+use CONFIG;
 
 #[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
 #[table_name = "devices"]
@@ -17,12 +21,65 @@ pub struct Device {
     /// https://github.com/bitwarden/core/tree/master/src/Core/Enums
     pub type_: i32,
     pub push_token: Option<String>,
+    /// Registration id generated here and sent to the push relay on first
+    /// registration, stable across token refreshes
+    pub push_uuid: Option<String>,
 
     pub refresh_token: String,
 
     pub twofactor_remember: Option<String>,
 }
 
+/// The way a grant was originally authenticated. Carried through token
+/// issuance so that a refreshed token preserves the scope of its original
+/// grant instead of silently being upgraded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Password,
+    ApiKey,
+    Sso,
+}
+
+impl AuthMethod {
+    /// OAuth scopes granted to this method. API-key grants are deliberately
+    /// denied `offline_access`.
+    pub fn scope(self) -> Vec<String> {
+        match self {
+            AuthMethod::ApiKey => vec!["api".into()],
+            _ => vec!["api".into(), "offline_access".into()],
+        }
+    }
+
+    /// Authentication method reference reported in the JWT.
+    pub fn amr(self) -> Vec<String> {
+        match self {
+            AuthMethod::Sso => vec!["external".into()],
+            _ => vec!["Application".into()],
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthMethod::Password => "password",
+            AuthMethod::ApiKey => "api_key",
+            AuthMethod::Sso => "sso",
+        }
+    }
+}
+
+impl std::str::FromStr for AuthMethod {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "password" => Ok(AuthMethod::Password),
+            "api_key" => Ok(AuthMethod::ApiKey),
+            "sso" => Ok(AuthMethod::Sso),
+            _ => Err(()),
+        }
+    }
+}
+
 /// Local methods
 impl Device {
     pub fn new(uuid: String, user_uuid: String, name: String, type_: i32) -> Self {
@@ -38,6 +95,7 @@ impl Device {
             type_,
 
             push_token: None,
+            push_uuid: None,
             refresh_token: String::new(),
             twofactor_remember: None,
         }
@@ -57,15 +115,99 @@ impl Device {
         self.twofactor_remember = None;
     }
 
+    /// Register this device with the Bitwarden push relay so that cipher and
+    /// folder sync events can later be delivered to it. A fresh `push_uuid` is
+    /// minted on the first registration and kept for the lifetime of the
+    /// device so the relay can address it independently of its install-generated
+    /// `uuid`.
+    pub fn register_push_device(&mut self) {
+        use uuid::Uuid;
+
+        if !CONFIG.push_enabled {
+            return;
+        }
+
+        let push_token = match self.push_token {
+            Some(ref token) => token.clone(),
+            None => return,
+        };
+
+        // Remember the previous value so a failed registration doesn't leave the
+        // device falsely marked as registered.
+        let previous_uuid = self.push_uuid.clone();
+        if self.push_uuid.is_none() {
+            self.push_uuid = Some(Uuid::new_v4().to_string());
+        }
+        let push_uuid = self.push_uuid.clone().unwrap();
+
+        let body = json!({
+            "deviceId": push_uuid,
+            "pushToken": push_token,
+            "userId": self.user_uuid,
+            "type": self.type_,
+            "identifier": self.uuid,
+        });
+
+        if let Err(e) = push::relay_post("/push/register", body) {
+            error!("An error occured while registering the device for push: {}", e);
+            // Roll back so a later save retries the registration instead of
+            // leaving this device permanently unreachable.
+            self.push_uuid = previous_uuid;
+        }
+    }
+
+    /// Drop this device's registration from the push relay. Called when the
+    /// device is deleted.
+    pub fn unregister_push_device(&self) {
+        if !CONFIG.push_enabled {
+            return;
+        }
 
-    pub fn refresh_tokens(&mut self, user: &super::User, orgs: Vec<super::UserOrganization>) -> (String, i64) {
-        // If there is no refresh token, we create one
-        if self.refresh_token.is_empty() {
-            use data_encoding::BASE64URL;
-            use crypto;
+        let push_uuid = match self.push_uuid {
+            Some(ref uuid) => uuid.clone(),
+            None => return,
+        };
 
-            self.refresh_token = BASE64URL.encode(&crypto::get_random_64());
+        let body = json!({ "ids": [push_uuid] });
+
+        if let Err(e) = push::relay_post("/push/delete", body) {
+            error!("An error occured while unregistering the device from push: {}", e);
         }
+    }
+
+
+    pub fn refresh_tokens(&mut self, user: &super::User, orgs: Vec<super::UserOrganization>, method: AuthMethod, conn: &DbConn) -> Result<(String, i64), Status> {
+        use super::RefreshToken;
+
+        // On a refresh we already hold a token: look it up and reject a missing
+        // or expired presentation so an expired refresh token can't be traded
+        // for a live one. The presented row is then rotated out (delete old,
+        // insert new) and the originating grant's auth method recovered so the
+        // reissued token preserves its scope. An empty token is the initial
+        // grant, which uses the supplied method. Prune other expired rows only
+        // after the presented one has been checked, so the expiry check never
+        // runs against a row we just deleted.
+        let method = if self.refresh_token.is_empty() {
+            method
+        } else {
+            let old = RefreshToken::find_by_token(&self.refresh_token, conn).ok_or(Status::Unauthorized)?;
+            if old.is_expired() {
+                old.delete(conn);
+                return Err(Status::Unauthorized);
+            }
+            let recovered = old.auth_method();
+            old.delete(conn);
+            recovered
+        };
+        RefreshToken::delete_expired(conn);
+
+        // Mint a fresh, independently-revocable refresh token for this grant and
+        // keep the device's column pointing at the current one. The originating
+        // auth method travels with the row so a later refresh reissues the same
+        // scope.
+        let refresh_token = RefreshToken::new(self.uuid.clone(), method);
+        refresh_token.save(conn);
+        self.refresh_token = refresh_token.token;
 
         // Update the expiration of the device and the last update date
         let time_now = Utc::now().naive_utc();
@@ -96,12 +238,12 @@ impl Device {
 
             sstamp: user.security_stamp.to_string(),
             device: self.uuid.to_string(),
-            scope: vec!["api".into(), "offline_access".into()],
-            amr: vec!["Application".into()],
+            scope: method.scope(),
+            amr: method.amr(),
         };
 
 
-        (encode_jwt(&claims), DEFAULT_VALIDITY.num_seconds())
+        Ok((encode_jwt(&claims), DEFAULT_VALIDITY.num_seconds()))
     }
 }
 
@@ -115,6 +257,12 @@ impl Device {
     pub fn save(&mut self, conn: &DbConn) -> bool {
         self.updated_at = Utc::now().naive_utc();
 
+        // A device that has a push token but was never registered with the
+        // relay gets a fresh push_uuid and is announced before being persisted.
+        if self.push_token.is_some() && self.push_uuid.is_none() {
+            self.register_push_device();
+        }
+
         match diesel::replace_into(devices::table)
             .values(&*self)
             .execute(&**conn) {
@@ -124,6 +272,9 @@ impl Device {
     }
 
     pub fn delete(self, conn: &DbConn) -> bool {
+        self.unregister_push_device();
+        super::RefreshToken::delete_all_by_device(&self.uuid, conn);
+
         match diesel::delete(devices::table.filter(
             devices::uuid.eq(self.uuid)))
             .execute(&**conn) {
@@ -0,0 +1,94 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use super::{AuthMethod, Device};
+use CONFIG;
+
+#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[table_name = "refresh_tokens"]
+#[belongs_to(Device, foreign_key = "device_uuid")]
+#[primary_key(uuid)]
+pub struct RefreshToken {
+    pub uuid: String,
+    pub device_uuid: String,
+    pub token: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    /// Auth method of the originating grant, as `AuthMethod::as_str`
+    pub auth_method: String,
+}
+
+/// Local methods
+impl RefreshToken {
+    pub fn new(device_uuid: String, method: AuthMethod) -> Self {
+        use data_encoding::BASE64URL;
+        use crypto;
+        use uuid::Uuid;
+
+        let now = Utc::now().naive_utc();
+
+        Self {
+            uuid: Uuid::new_v4().to_string(),
+            device_uuid,
+            token: BASE64URL.encode(&crypto::get_random_64()),
+            created_at: now,
+            expires_at: now + Duration::days(CONFIG.refresh_token_days),
+            auth_method: method.as_str().to_string(),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at < Utc::now().naive_utc()
+    }
+
+    /// Recover the originating auth method, defaulting to `Password` for rows
+    /// written before the method was tracked.
+    pub fn auth_method(&self) -> AuthMethod {
+        self.auth_method.parse().unwrap_or(AuthMethod::Password)
+    }
+}
+
+use diesel;
+use diesel::prelude::*;
+use db::DbConn;
+use db::schema::refresh_tokens;
+
+/// Database methods
+impl RefreshToken {
+    pub fn save(&self, conn: &DbConn) -> bool {
+        match diesel::replace_into(refresh_tokens::table)
+            .values(self)
+            .execute(&**conn) {
+            Ok(1) => true, // One row inserted
+            _ => false,
+        }
+    }
+
+    pub fn delete(self, conn: &DbConn) -> bool {
+        match diesel::delete(refresh_tokens::table.filter(
+            refresh_tokens::uuid.eq(self.uuid)))
+            .execute(&**conn) {
+            Ok(1) => true, // One row deleted
+            _ => false,
+        }
+    }
+
+    pub fn delete_expired(conn: &DbConn) -> bool {
+        diesel::delete(refresh_tokens::table.filter(
+            refresh_tokens::expires_at.lt(Utc::now().naive_utc())))
+            .execute(&**conn)
+            .is_ok()
+    }
+
+    pub fn delete_all_by_device(device_uuid: &str, conn: &DbConn) -> bool {
+        diesel::delete(refresh_tokens::table.filter(
+            refresh_tokens::device_uuid.eq(device_uuid)))
+            .execute(&**conn)
+            .is_ok()
+    }
+
+    pub fn find_by_token(token: &str, conn: &DbConn) -> Option<Self> {
+        refresh_tokens::table
+            .filter(refresh_tokens::token.eq(token))
+            .first::<Self>(&**conn).ok()
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::Mutex;
+
+use chrono::{NaiveDateTime, Utc};
+use reqwest::{header::AUTHORIZATION, Client};
+use serde_json::Value;
+
+use CONFIG;
+
+/// Bearer token obtained from the relay's `/connect/token` endpoint, cached
+/// until shortly before it expires so that we don't perform an OAuth round-trip
+/// on every push.
+struct AccessToken {
+    token: String,
+    expires_at: NaiveDateTime,
+}
+
+lazy_static! {
+    static ref ACCESS_TOKEN: Mutex<Option<AccessToken>> = Mutex::new(None);
+}
+
+/// POST `body` to `path` on the configured push relay with a valid relay bearer
+/// token, obtaining (or refreshing) that token as needed.
+pub fn relay_post(path: &str, body: Value) -> Result<(), String> {
+    let token = get_token()?;
+
+    let url = format!("{}{}", CONFIG.push_relay_base_uri, path);
+    Client::new()
+        .post(&url)
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .json(&body)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// Return a cached relay access token, refreshing it from `/connect/token` when
+/// it is missing or expired.
+fn get_token() -> Result<String, String> {
+    // Fast path: return a still-valid cached token without holding the guard
+    // across the network round-trip below.
+    {
+        let cache = ACCESS_TOKEN.lock().unwrap();
+        if let Some(ref cached) = *cache {
+            if cached.expires_at > Utc::now().naive_utc() {
+                return Ok(cached.token.clone());
+            }
+        }
+    }
+
+    let now = Utc::now().naive_utc();
+    let url = format!("{}/connect/token", CONFIG.push_relay_base_uri);
+    let mut res = Client::new()
+        .post(&url)
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("scope", "api.push"),
+            ("client_id", &format!("installation.{}", CONFIG.push_installation_id)),
+            ("client_secret", &CONFIG.push_installation_key),
+        ])
+        .send()
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+
+    let json: Value = res.json().map_err(|e| e.to_string())?;
+
+    let token = json["access_token"]
+        .as_str()
+        .ok_or_else(|| "Missing access_token in relay response".to_string())?
+        .to_string();
+    // Refresh a minute early to stay clear of the expiry boundary.
+    let expires_in = json["expires_in"].as_i64().unwrap_or(3600) - 60;
+
+    // Re-acquire the guard only to store the result, so the request above never
+    // serialized other pushers or poisoned the lock on failure.
+    *ACCESS_TOKEN.lock().unwrap() = Some(AccessToken {
+        token: token.clone(),
+        expires_at: now + chrono::Duration::seconds(expires_in.max(0)),
+    });
+
+    Ok(token)
+}
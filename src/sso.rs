@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use reqwest::Client;
+use serde_json::Value;
+
+use db::models::{AuthMethod, Device, RefreshToken, User};
+use db::DbConn;
+use CONFIG;
+
+/// How long a cached discovery document / JWKS is trusted before it is
+/// refetched, so we don't hit the IdP on every login.
+const DISCOVERY_TTL_HOURS: i64 = 1;
+
+/// An in-flight authorization request, remembered between the redirect and the
+/// callback so that `state` and `nonce` can be verified.
+struct AuthRequest {
+    nonce: String,
+    expires_at: NaiveDateTime,
+}
+
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks: Value,
+    expires_at: NaiveDateTime,
+}
+
+lazy_static! {
+    /// Pending authorization requests keyed by their opaque `state`.
+    static ref AUTH_REQUESTS: Mutex<HashMap<String, AuthRequest>> = Mutex::new(HashMap::new());
+    static ref DISCOVERY: Mutex<Option<Discovery>> = Mutex::new(None);
+}
+
+/// Build the authorization-endpoint URL to redirect the user to, remembering
+/// the generated `state`/`nonce` so the callback can reject replays and CSRF.
+pub fn authorize_url() -> Result<String, String> {
+    use data_encoding::BASE64URL_NOPAD;
+    use crypto;
+
+    let discovery = get_discovery()?;
+
+    let state = BASE64URL_NOPAD.encode(&crypto::get_random(vec![0u8; 16]));
+    let nonce = BASE64URL_NOPAD.encode(&crypto::get_random(vec![0u8; 16]));
+
+    let now = Utc::now().naive_utc();
+    {
+        let mut requests = AUTH_REQUESTS.lock().unwrap();
+        // Abandoned logins never reach the callback, so prune expired entries
+        // here to keep this public endpoint from growing unbounded.
+        requests.retain(|_, r| r.expires_at > now);
+        requests.insert(
+            state.clone(),
+            AuthRequest {
+                nonce: nonce.clone(),
+                expires_at: now + Duration::minutes(10),
+            },
+        );
+    }
+
+    // Build the query with proper form-urlencoding: scopes are space-separated
+    // and redirect_uri contains reserved characters, both of which are invalid
+    // raw in a URL.
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("client_id", &CONFIG.sso_client_id)
+        .append_pair("response_type", "code")
+        .append_pair("scope", &CONFIG.sso_scopes)
+        .append_pair("redirect_uri", &redirect_uri())
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .finish();
+
+    Ok(format!("{}?{}", discovery.authorization_endpoint, query))
+}
+
+/// Complete an authorization-code login: verify `state`, exchange the code,
+/// validate the returned id_token (signature + `nonce` + `email_verified`),
+/// match or provision the local user and device, and mint our own JWT with
+/// `amr: ["external"]`.
+pub fn exchange_code(state: &str, code: &str, device: &mut Device, conn: &DbConn) -> Result<(String, i64), String> {
+    let request = AUTH_REQUESTS
+        .lock()
+        .unwrap()
+        .remove(state)
+        .ok_or_else(|| "Unknown or expired SSO state".to_string())?;
+
+    if request.expires_at < Utc::now().naive_utc() {
+        return Err("SSO state expired".to_string());
+    }
+
+    let discovery = get_discovery()?;
+
+    let mut res = Client::new()
+        .post(&discovery.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &redirect_uri()),
+            ("client_id", &CONFIG.sso_client_id),
+            ("client_secret", &CONFIG.sso_client_secret),
+        ])
+        .send()
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+
+    let tokens: Value = res.json().map_err(|e| e.to_string())?;
+    let id_token = tokens["id_token"]
+        .as_str()
+        .ok_or_else(|| "Missing id_token in token response".to_string())?;
+
+    let claims = validate_id_token(id_token, &request.nonce, &discovery)?;
+
+    let email = claims["email"].as_str().ok_or_else(|| "id_token has no email".to_string())?;
+    if claims["email_verified"].as_bool() != Some(true) {
+        return Err("IdP has not verified this email address".to_string());
+    }
+    let subject = claims["sub"].as_str().ok_or_else(|| "id_token has no subject".to_string())?;
+
+    // Rebind to the existing account by IdP subject when we've seen it before,
+    // otherwise provision a fresh user for it.
+    let mut user = match User::find_by_sso_identifier(subject, conn) {
+        Some(user) => user,
+        None => match User::find_by_mail(email, conn) {
+            // A local account with this email already exists but has never been
+            // linked to this IdP. Silently binding it would let any IdP that
+            // asserts email_verified take over a password account, so only bind
+            // when the operator has explicitly opted in.
+            Some(mut existing) => {
+                if !CONFIG.sso_bind_existing {
+                    return Err("An account with this email already exists; SSO binding is disabled".to_string());
+                }
+                existing.sso_identifier = Some(subject.to_string());
+                existing
+            }
+            None => {
+                let mut user = User::new(email.to_string());
+                user.sso_identifier = Some(subject.to_string());
+                user
+            }
+        },
+    };
+    user.save(conn);
+
+    device.user_uuid = user.uuid.clone();
+    // An SSO login is a fresh grant even on a device that already holds a
+    // refresh token: drop that token so the new one carries the SSO method.
+    if let Some(old) = RefreshToken::find_by_token(&device.refresh_token, conn) {
+        old.delete(conn);
+    }
+    device.refresh_token = String::new();
+    let result = device
+        .refresh_tokens(&user, Vec::new(), AuthMethod::Sso, conn)
+        .map_err(|_| "Failed to issue token for SSO login".to_string())?;
+    device.save(conn);
+
+    Ok(result)
+}
+
+fn redirect_uri() -> String {
+    format!("{}/identity/sso/callback", CONFIG.domain)
+}
+
+/// Validate an id_token's signature against the cached JWKS and confirm its
+/// issuer, audience and `nonce`, returning the decoded claims.
+fn validate_id_token(id_token: &str, nonce: &str, discovery: &Discovery) -> Result<Value, String> {
+    let kid = decode_header(id_token)
+        .map_err(|e| e.to_string())?
+        .kid
+        .ok_or_else(|| "id_token header has no kid".to_string())?;
+
+    let key = discovery.jwks["keys"]
+        .as_array()
+        .and_then(|keys| keys.iter().find(|k| k["kid"].as_str() == Some(kid.as_str())))
+        .ok_or_else(|| "No matching JWKS key for id_token".to_string())?;
+
+    let n = key["n"].as_str().ok_or_else(|| "JWKS key missing modulus".to_string())?;
+    let e = key["e"].as_str().ok_or_else(|| "JWKS key missing exponent".to_string())?;
+
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.iss = Some(CONFIG.sso_authority.clone());
+    validation.set_audience(&[CONFIG.sso_client_id.clone()]);
+
+    let key = DecodingKey::from_rsa_components(n, e);
+    let token = decode::<Value>(id_token, &key, &validation).map_err(|e| e.to_string())?;
+
+    if token.claims["nonce"].as_str() != Some(nonce) {
+        return Err("id_token nonce mismatch".to_string());
+    }
+
+    Ok(token.claims)
+}
+
+/// Return the cached discovery document and JWKS, refetching them from the
+/// authority's well-known endpoint when missing or stale.
+fn get_discovery() -> Result<Discovery, String> {
+    {
+        let cache = DISCOVERY.lock().unwrap();
+        if let Some(ref d) = *cache {
+            if d.expires_at > Utc::now().naive_utc() {
+                return Ok(d.clone());
+            }
+        }
+    }
+
+    let url = format!("{}/.well-known/openid-configuration", CONFIG.sso_authority);
+    let mut res = Client::new()
+        .get(&url)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+    let doc: Value = res.json().map_err(|e| e.to_string())?;
+
+    let authorization_endpoint = doc["authorization_endpoint"].as_str().ok_or("Discovery missing authorization_endpoint")?.to_string();
+    let token_endpoint = doc["token_endpoint"].as_str().ok_or("Discovery missing token_endpoint")?.to_string();
+    let jwks_uri = doc["jwks_uri"].as_str().ok_or("Discovery missing jwks_uri")?;
+
+    let mut res = Client::new()
+        .get(jwks_uri)
+        .send()
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?;
+    let jwks: Value = res.json().map_err(|e| e.to_string())?;
+
+    let discovery = Discovery {
+        authorization_endpoint,
+        token_endpoint,
+        jwks,
+        expires_at: Utc::now().naive_utc() + Duration::hours(DISCOVERY_TTL_HOURS),
+    };
+
+    let cloned = discovery.clone();
+    *DISCOVERY.lock().unwrap() = Some(discovery);
+    Ok(cloned)
+}
+
+impl Clone for Discovery {
+    fn clone(&self) -> Self {
+        Discovery {
+            authorization_endpoint: self.authorization_endpoint.clone(),
+            token_endpoint: self.token_endpoint.clone(),
+            jwks: self.jwks.clone(),
+            expires_at: self.expires_at,
+        }
+    }
+}